@@ -1,15 +1,54 @@
-use anyhow::{Error, Ok, Result};
+use anyhow::{Context, Error, Ok, Result};
 use clap::Parser;
 use env_logger::{Builder, Target};
 use indicatif::ProgressIterator;
 use log::LevelFilter;
 use rayon::prelude::*;
-use rust_htslib::{bam, bam::Read};
-use std::collections::HashSet;
+use rust_htslib::{bam, bam::record::Aux, bam::Read};
+use smallvec::SmallVec;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogLevel {
+    Off,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Writer that flushes after every write, so a `tail -f` on the log file
+/// sees routing progress immediately instead of waiting on an internal buffer.
+struct UnbufferedWriter<W: Write>(W);
+
+impl<W: Write> Write for UnbufferedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        std::result::Result::Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
 
 #[derive(Parser, Debug, PartialEq, Eq)]
 #[clap(
@@ -32,6 +71,27 @@ struct Opts {
     #[clap(short, long)]
     reads: Vec<PathBuf>,
 
+    /// Write records routed to no list output to this bam: reads matched by
+    /// none of the input lists, and repeat records of a listed name whose
+    /// outputs were already filled by an earlier record
+    #[clap(long)]
+    unplaced: Option<PathBuf>,
+
+    /// Write a TSV manifest (out bam, list, requested, written, missing) for the split
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Write each read to every matching list's output instead of just the first
+    #[clap(long)]
+    allow_duplicates: bool,
+
+    /// Two-character aux tag to annotate each written record with the
+    /// basename of the read list it matched (requires --allow-duplicates
+    /// to be meaningful when a read is in more than one list). Overwrites
+    /// any pre-existing aux field of the same name on the record.
+    #[clap(long)]
+    tag: Option<String>,
+
     /// Logging level [-v: Info, -vv: Debug, -vvv: Trace]
     #[clap(
             global = true,
@@ -41,16 +101,42 @@ struct Opts {
             help_heading = "Debug-Options"
         )]
     pub verbose: u8,
+
+    /// Explicit log level, overriding the -v count (handy for scripts)
+    #[clap(long, help_heading = "Debug-Options")]
+    pub log_level: Option<LogLevel>,
+
+    /// Write log records to this file instead of stderr
+    #[clap(long, help_heading = "Debug-Options")]
+    pub log_file: Option<PathBuf>,
+
+    /// Flush the log file after every record instead of buffering
+    /// (only meaningful with --log-file; useful when tailing long runs)
+    #[clap(long, help_heading = "Debug-Options")]
+    pub no_buffering: bool,
 }
 
 fn main() -> Result<(), Error> {
+    let start = Instant::now();
     let opts = Opts::parse();
-    set_log_level(&opts);
+    set_log_level(&opts)?;
+
+    if let Some(tag) = &opts.tag {
+        anyhow::ensure!(
+            tag.len() == 2,
+            "--tag must be exactly two characters, got {:?}",
+            tag
+        );
+    }
 
-    let reads = opts
+    // parse every list and, in the same parallel pass, drain it straight into
+    // a per-list partial read-name -> output-index map (no second pass over
+    // all read names, and no read name is ever held in two places at once)
+    let built = opts
         .reads
         .par_iter()
-        .map(|path| {
+        .enumerate()
+        .map(|(i, path)| {
             let file = File::open(path).unwrap();
             let reader = BufReader::new(file);
             let mut set = HashSet::new();
@@ -58,8 +144,13 @@ fn main() -> Result<(), Error> {
                 set.insert(line.unwrap().trim().to_string());
             }
             let path_s = path.as_os_str().to_str().unwrap();
-            log::info!("{} had {} reads", path_s, set.len());
-            (path_s, set)
+            let requested = set.len();
+            log::info!("{} had {} reads", path_s, requested);
+            let mut partial_index: HashMap<String, SmallVec<[usize; 4]>> = HashMap::new();
+            for name in set.drain() {
+                partial_index.entry(name).or_default().push(i);
+            }
+            (path_s, requested, partial_index)
         })
         .collect::<Vec<_>>();
 
@@ -70,47 +161,148 @@ fn main() -> Result<(), Error> {
 
     // make outputs
     let mut outs = Vec::new();
-    for (path, set) in &reads {
-        let out_path = Path::new(&path).with_extension("bam");
-        let mut out = bam::Writer::from_path(out_path, &header, bam::Format::Bam)?;
+    for (path, requested, _partial_index) in &built {
+        let out_path = Path::new(path).with_extension("bam");
+        let mut out = bam::Writer::from_path(&out_path, &header, bam::Format::Bam)?;
         out.set_threads(opts.threads)?;
-        outs.push((out, set.clone()));
+        let tag_value = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| (*path).to_string());
+        outs.push((out, out_path, *path, *requested, tag_value, 0usize));
     }
+
+    // merge the per-list partial indices with a parallel reduce, so the
+    // read-name -> output-index map is built without a single-threaded pass
+    // over every read name across every list
+    let mut index: HashMap<String, SmallVec<[usize; 4]>> = built
+        .into_par_iter()
+        .map(|(_path, _requested, partial_index)| partial_index)
+        .reduce(HashMap::new, |mut a, b| {
+            for (name, idxs) in b {
+                a.entry(name).or_default().extend(idxs);
+            }
+            a
+        });
+
+    // optional bam for reads that match none of the input lists
+    let mut unplaced = match &opts.unplaced {
+        Some(unplaced_path) => {
+            let mut out = bam::Writer::from_path(unplaced_path, &header, bam::Format::Bam)?;
+            out.set_threads(opts.threads)?;
+            Some(out)
+        }
+        None => None,
+    };
+
     // write results
     bam.records().progress_count(1).try_for_each(|record| {
         let record = record?;
         let query_name = std::str::from_utf8(record.qname())?;
-        for (out, set) in &mut outs {
-            if set.remove(query_name) {
-                out.write(&record)?;
-                break;
+        let dests = match index.get_mut(query_name) {
+            Some(dests) if !dests.is_empty() => {
+                // single-assignment mode consumes only the highest-priority
+                // (first list order) destination, so a later duplicate
+                // occurrence of this name can still land on the next one
+                if opts.allow_duplicates {
+                    std::mem::take(dests)
+                } else {
+                    SmallVec::from_elem(dests.remove(0), 1)
+                }
+            }
+            _ => SmallVec::new(),
+        };
+        if dests.is_empty() {
+            if let Some(unplaced) = &mut unplaced {
+                unplaced.write(&record)?;
+            }
+        } else {
+            for i in dests {
+                let (out, _out_path, _list_path, _requested, tag_value, seen) = &mut outs[i];
+                match &opts.tag {
+                    Some(tag) => {
+                        let mut tagged = record.clone();
+                        // overwrite any pre-existing aux field of this name (e.g. a real
+                        // alignment's RG/NM/MD) rather than erroring on the duplicate
+                        tagged.remove_aux(tag.as_bytes()).ok();
+                        tagged.push_aux(tag.as_bytes(), Aux::String(tag_value))?;
+                        out.write(&tagged)?;
+                    }
+                    None => out.write(&record)?,
+                }
+                *seen += 1;
             }
         }
+        if index.get(query_name).is_some_and(SmallVec::is_empty) {
+            index.remove(query_name);
+        }
         Ok(())
     })?;
 
-    // get unplaced
-    for (_path, set) in outs {
-        log::info!("had {} unplaced reads", set.len());
+    // report and, optionally, write out a reconciliation manifest
+    let elapsed = start.elapsed().as_secs_f64();
+    let mut manifest_lines = vec!["out_bam\tread_list\trequested\twritten\tmissing".to_string()];
+    for (_out, out_path, list_path, requested, _tag_value, seen) in &outs {
+        let missing = requested - seen;
+        log::info!(
+            "split_summary read_list={} requested={} written={} missing={} elapsed_secs={:.3}",
+            list_path,
+            requested,
+            seen,
+            missing,
+            elapsed
+        );
+        manifest_lines.push(format!(
+            "{}\t{}\t{}\t{}\t{}",
+            out_path.display(),
+            list_path,
+            requested,
+            seen,
+            missing
+        ));
+    }
+    if let Some(manifest_path) = &opts.manifest {
+        std::fs::write(manifest_path, manifest_lines.join("\n") + "\n")?;
     }
 
     Ok(())
 }
 
-fn set_log_level(opts: &Opts) {
-    // set the logging level
-    let min_log_level = match opts.verbose {
-        0 => LevelFilter::Warn,
-        1 => LevelFilter::Info,
-        2 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
+fn set_log_level(opts: &Opts) -> Result<(), Error> {
+    // an explicit --log-level always wins over the -v count
+    let min_log_level = opts.log_level.map(LevelFilter::from).unwrap_or_else(|| {
+        match opts.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    });
+
+    let mut builder = Builder::new();
+    builder.filter(None, min_log_level);
 
-    Builder::new()
-        .target(Target::Stderr)
-        .filter(None, min_log_level)
-        .init();
+    match &opts.log_file {
+        Some(log_file) => {
+            let file = File::create(log_file)
+                .with_context(|| format!("cannot create --log-file {log_file:?}"))?;
+            let writer: Box<dyn Write + Send> = if opts.no_buffering {
+                Box::new(UnbufferedWriter(file))
+            } else {
+                Box::new(BufWriter::new(file))
+            };
+            builder.target(Target::Pipe(writer));
+        }
+        None => {
+            builder.target(Target::Stderr);
+        }
+    }
+
+    // let a RUST_LOG-style env var override the `-v` count for per-module tuning
+    builder.parse_default_env();
+    builder.init();
 
     log::debug!("DEBUG logging enabled");
     log::trace!("TRACE logging enabled");
+    Ok(())
 }